@@ -1,91 +1,310 @@
 use bytes::Buf;
-use futures::{Async, Future, Poll};
+use futures::{self, Async, Future, Poll};
 use http;
 use hyper::body::Payload;
+use std::error::Error as StdError;
 use std::marker::PhantomData;
+use std::sync::Arc;
+use std::{fmt, mem};
 
 use proxy::Error;
 use svc;
 
 pub enum Fallback<P> {
-    Rejected(http::Request<P>),
+    Rejected(Rejected<P>),
     Inner(Error),
 }
 
-#[derive(Debug, Clone)]
-pub struct Layer<A, B, P> {
-    primary_layer: A,
-    fallback_layer: B,
-    _marker: PhantomData<fn(P)>,
+/// Why a stage declined to handle a request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RejectReason {
+    /// The stage declined the request without a more specific reason.
+    Declined,
+}
+
+/// A cheap, non-allocating decline signal carrying the reason and the
+/// recovered request.
+pub struct Rejected<P> {
+    pub reason: RejectReason,
+    request: http::Request<P>,
+}
+
+impl<P> Rejected<P> {
+    pub fn new(reason: RejectReason, request: http::Request<P>) -> Self {
+        Rejected { reason, request }
+    }
+
+    pub fn into_request(self) -> http::Request<P> {
+        self.request
+    }
+}
+
+/// Evaluated against a stage's successful response to trigger a retry.
+pub type ShouldFallback<Q> = Arc<dyn Fn(&http::Response<Q>) -> bool + Send + Sync>;
+
+/// A request's cheap-to-clone parts, captured for a predicate-driven retry
+/// only when its body is empty (it's rebuilt with `P::default()`).
+#[derive(Clone)]
+struct RequestHead {
+    method: http::Method,
+    uri: http::Uri,
+    headers: http::HeaderMap,
+}
+
+impl RequestHead {
+    fn capture<P>(req: &http::Request<P>) -> Self {
+        RequestHead {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            headers: req.headers().clone(),
+        }
+    }
+
+    fn into_request<P: Default>(self) -> http::Request<P> {
+        let mut req = http::Request::new(P::default());
+        *req.method_mut() = self.method;
+        *req.uri_mut() = self.uri;
+        *req.headers_mut() = self.headers;
+        req
+    }
+}
+
+/// True if `req` is a `CONNECT` request, or carries both `Connection: upgrade`
+/// and an `Upgrade` header.
+fn wants_upgrade<P>(req: &http::Request<P>) -> bool {
+    if req.method() == http::Method::CONNECT {
+        return true;
+    }
+
+    let connection_has_upgrade = req
+        .headers()
+        .get(http::header::CONNECTION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            v.split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        })
+        .unwrap_or(false);
+
+    connection_has_upgrade && req.headers().contains_key(http::header::UPGRADE)
 }
 
+/// An erased `http::Response` body.
+pub struct BoxBody(Box<dyn Payload<Data = BoxBuf, Error = Error> + Send>);
+
+/// The `Data` of a `BoxBody`.
+pub struct BoxBuf(Box<dyn Buf + Send>);
+
+struct EraseBody<B>(B);
+
+/// Returned when the last stage in a chain rejects the request.
 #[derive(Debug)]
-pub struct MakeSvc<A, B, P> {
-    primary_make: A,
-    fallback_make: B,
+pub struct NoFallbackAvailable(RejectReason);
+
+/// The default, never-invoked upgrade branch for a chain with no `with_upgrade`.
+#[derive(Debug, Default)]
+pub struct NoUpgrade<P, Q>(PhantomData<fn(P) -> Q>);
+
+/// The `Service` produced by `NoUpgrade`.
+#[derive(Debug, Default)]
+pub struct NoUpgradeService<P, Q>(PhantomData<fn(P) -> Q>);
+
+/// All stages share the concrete type `L`; see [`layer`].
+pub struct Layer<L, P, Q, U = NoUpgrade<P, Q>> {
+    stages: Vec<L>,
+    should_fallback: Option<ShouldFallback<Q>>,
+    upgrade_layer: Option<U>,
     _marker: PhantomData<fn(P)>,
 }
 
-#[derive(Debug)]
-pub struct MakeFuture<A, B, P> {
-    primary: A,
-    fallback: B,
+pub struct MakeSvc<M, P, Q, U> {
+    stages: Vec<M>,
+    should_fallback: Option<ShouldFallback<Q>>,
+    upgrade_make: Option<U>,
     _marker: PhantomData<fn(P)>,
 }
 
-pub struct Service<A, B, P> {
-    primary_service: A,
-    fallback_service: B,
+pub struct MakeFuture<F, P, Q, U> {
+    stages: Vec<F>,
+    should_fallback: Option<ShouldFallback<Q>>,
+    upgrade_future: Option<U>,
     _marker: PhantomData<fn(P)>,
 }
 
-#[derive(Debug)]
-pub enum ResponseFuture<A, B, P>
+pub struct Service<S, P, Q, U> {
+    stages: Vec<S>,
+    should_fallback: Option<ShouldFallback<Q>>,
+    upgrade: Option<U>,
+    _marker: PhantomData<fn(P)>,
+}
+
+pub enum ResponseFuture<S, US, P>
 where
-    A: Future<Error = Fallback<P>>,
-    B: svc::Service<http::Request<P>>,
-    B::Error: Into<Error>,
+    S: svc::Service<http::Request<P>>,
+    US: svc::Service<http::Request<P>>,
     P: Payload,
 {
-    Primary {
-        future: A,
-        fallback: B,
+    Pending {
+        future: S::Future,
+        stages: Vec<S>,
+        should_fallback: Option<ShouldFallback<S::Response>>,
+        head: Option<RequestHead>,
     },
-    FallbackPending {
-        fallback: B,
+    NextReady {
+        next: S,
+        stages: Vec<S>,
+        should_fallback: Option<ShouldFallback<S::Response>>,
+        head: Option<RequestHead>,
         request: Option<http::Request<P>>,
     },
-    Fallback(B::Future),
-}
-
-#[derive(Clone, Debug)]
-pub enum Body<A, B> {
-    A(A),
-    B(B),
+    Upgrade(US::Future),
 }
 
-pub fn layer<A, B, P>(primary_layer: A, fallback_layer: B) -> Layer<A, B, P> {
+/// Builds a fallback chain out of an ordered list of stage layers.
+///
+/// All stages must share one concrete layer type `L`; a chain of
+/// genuinely different service types needs to unify them first, e.g. by
+/// boxing each stage.
+pub fn layer<L, P, Q>(stages: Vec<L>) -> Layer<L, P, Q> {
+    assert!(
+        !stages.is_empty(),
+        "a fallback chain must have at least one stage"
+    );
     Layer {
-        primary_layer,
-        fallback_layer,
+        stages,
+        should_fallback: None,
+        upgrade_layer: None,
         _marker: PhantomData,
     }
 }
 
-// === impl Layer ===
+impl<L, P, Q, U> Layer<L, P, Q, U> {
+    /// Also falls back to the next stage when a successful response
+    /// matches `should_fallback`. Only requests with an empty body are
+    /// retried this way; for any other request the response stands.
+    pub fn with_predicate<F>(self, should_fallback: F) -> Self
+    where
+        F: Fn(&http::Response<Q>) -> bool + Send + Sync + 'static,
+    {
+        Layer {
+            should_fallback: Some(Arc::new(should_fallback)),
+            ..self
+        }
+    }
+
+    /// Routes HTTP upgrade requests to `upgrade_layer` instead of the
+    /// ordinary fallback stages.
+    pub fn with_upgrade<U2>(self, upgrade_layer: U2) -> Layer<L, P, Q, U2> {
+        Layer {
+            stages: self.stages,
+            should_fallback: self.should_fallback,
+            upgrade_layer: Some(upgrade_layer),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<L: Clone, P, Q, U: Clone> Clone for Layer<L, P, Q, U> {
+    fn clone(&self) -> Self {
+        Self {
+            stages: self.stages.clone(),
+            should_fallback: self.should_fallback.clone(),
+            upgrade_layer: self.upgrade_layer.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<L: fmt::Debug, P, Q, U: fmt::Debug> fmt::Debug for Layer<L, P, Q, U> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Layer")
+            .field("stages", &self.stages)
+            .field("should_fallback", &self.should_fallback.is_some())
+            .field("upgrade_layer", &self.upgrade_layer)
+            .finish()
+    }
+}
+
+// === impl NoFallbackAvailable ===
+
+impl fmt::Display for NoFallbackAvailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no more fallback stages to try ({:?})", self.0)
+    }
+}
+
+impl StdError for NoFallbackAvailable {}
+
+// === impl NoUpgrade ===
+
+impl<P, Q> Clone for NoUpgrade<P, Q> {
+    fn clone(&self) -> Self {
+        NoUpgrade(PhantomData)
+    }
+}
+
+impl<M, P, Q> svc::Layer<M> for NoUpgrade<P, Q> {
+    type Service = NoUpgrade<P, Q>;
+
+    fn layer(&self, _inner: M) -> Self::Service {
+        NoUpgrade(PhantomData)
+    }
+}
+
+impl<T, P, Q> svc::Service<T> for NoUpgrade<P, Q> {
+    type Response = NoUpgradeService<P, Q>;
+    type Error = Error;
+    type Future = futures::future::FutureResult<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        Ok(Async::Ready(()))
+    }
+
+    fn call(&mut self, _target: T) -> Self::Future {
+        futures::future::ok(NoUpgradeService(PhantomData))
+    }
+}
+
+// === impl NoUpgradeService ===
+
+impl<P, Q> Clone for NoUpgradeService<P, Q> {
+    fn clone(&self) -> Self {
+        NoUpgradeService(PhantomData)
+    }
+}
+
+impl<P, Q> svc::Service<http::Request<P>> for NoUpgradeService<P, Q> {
+    type Response = http::Response<Q>;
+    type Error = Error;
+    type Future = futures::future::FutureResult<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        Ok(Async::Ready(()))
+    }
 
-impl<A, B, P, M> svc::Layer<M> for Layer<A, B, P>
+    fn call(&mut self, _req: http::Request<P>) -> Self::Future {
+        unreachable!("the upgrade branch is never called when no upgrade layer is configured")
+    }
+}
+
+impl<L, P, Q, U, M> svc::Layer<M> for Layer<L, P, Q, U>
 where
     M: Clone,
-    A: svc::Layer<M>,
-    B: svc::Layer<M>,
+    L: svc::Layer<M>,
+    U: svc::Layer<M>,
 {
-    type Service = MakeSvc<A::Service, B::Service, P>;
+    type Service = MakeSvc<L::Service, P, Q, U::Service>;
 
     fn layer(&self, inner: M) -> Self::Service {
+        let stages = self
+            .stages
+            .iter()
+            .map(|stage| stage.layer(inner.clone()))
+            .collect();
         MakeSvc {
-            primary_make: self.primary_layer.layer(inner.clone()),
-            fallback_make: self.fallback_layer.layer(inner),
+            stages,
+            should_fallback: self.should_fallback.clone(),
+            upgrade_make: self.upgrade_layer.as_ref().map(|u| u.layer(inner)),
             _marker: PhantomData,
         }
     }
@@ -93,75 +312,100 @@ where
 
 // === impl MakeSvc ===
 
-impl<A, B, P, T> svc::Service<T> for MakeSvc<A, B, P>
+impl<M, P, Q, U, T> svc::Service<T> for MakeSvc<M, P, Q, U>
 where
-    A: svc::Service<T>,
-    A::Response: svc::Service<http::Request<P>>,
-    A::Error: Into<Error>,
-    B: svc::Service<T>,
-    B::Response: svc::Service<http::Request<P>>,
-    B::Error: Into<Error>,
+    M: svc::Service<T>,
+    M::Response: svc::Service<http::Request<P>>,
+    M::Error: Into<Error>,
+    U: svc::Service<T>,
+    U::Response: svc::Service<http::Request<P>>,
+    U::Error: Into<Error>,
     T: Clone,
 {
-    type Response = Service<A::Response, B::Response, P>;
-    type Future = MakeFuture<A::Future, B::Future, P>;
+    type Response = Service<M::Response, P, Q, U::Response>;
+    type Future = MakeFuture<M::Future, P, Q, U::Future>;
     type Error = Error;
 
     fn poll_ready(&mut self) -> Poll<(), Self::Error> {
-        let p = self.primary_make.poll_ready().map_err(Into::into)?;
-        let f = self.fallback_make.poll_ready().map_err(Into::into)?;
-        if p.is_ready() && f.is_ready() {
-            Ok(Async::Ready(()))
-        } else {
-            Ok(Async::NotReady)
+        let mut ready = true;
+        for stage in &mut self.stages {
+            if stage.poll_ready().map_err(Into::into)?.is_not_ready() {
+                ready = false;
+            }
+        }
+        if let Some(ref mut upgrade) = self.upgrade_make {
+            if upgrade.poll_ready().map_err(Into::into)?.is_not_ready() {
+                ready = false;
+            }
         }
+        Ok(if ready {
+            Async::Ready(())
+        } else {
+            Async::NotReady
+        })
     }
 
     fn call(&mut self, target: T) -> Self::Future {
-        let primary = self.primary_make.call(target.clone());
-        let fallback = self.fallback_make.call(target);
+        let stages = self
+            .stages
+            .iter_mut()
+            .map(|stage| stage.call(target.clone()))
+            .collect();
+        let upgrade_future = self
+            .upgrade_make
+            .as_mut()
+            .map(|upgrade| upgrade.call(target));
 
         MakeFuture {
-            primary,
-            fallback,
+            stages,
+            should_fallback: self.should_fallback.clone(),
+            upgrade_future,
             _marker: PhantomData,
         }
     }
 }
 
-impl<A, B, P> Clone for MakeSvc<A, B, P>
+impl<M, P, Q, U> Clone for MakeSvc<M, P, Q, U>
 where
-    A: Clone,
-    B: Clone,
+    M: Clone,
+    U: Clone,
 {
     fn clone(&self) -> Self {
         Self {
-            primary_make: self.primary_make.clone(),
-            fallback_make: self.fallback_make.clone(),
+            stages: self.stages.clone(),
+            should_fallback: self.should_fallback.clone(),
+            upgrade_make: self.upgrade_make.clone(),
             _marker: PhantomData,
         }
     }
 }
 
-// === impl MakeSvc ===
+// === impl MakeFuture ===
 
-impl<A, B, P> Future for MakeFuture<A, B, P>
+impl<F, P, Q, U> Future for MakeFuture<F, P, Q, U>
 where
-    A: Future,
-    A::Item: svc::Service<http::Request<P>>,
-    B: Future,
-    B::Item: svc::Service<http::Request<P>>,
+    F: Future,
+    F::Item: svc::Service<http::Request<P>>,
+    U: Future,
+    U::Item: svc::Service<http::Request<P>>,
 {
-    type Item = Service<A::Item, B::Item, P>;
+    type Item = Service<F::Item, P, Q, U::Item>;
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let primary_service = try_ready!(self.primary.poll().map_err(Into::into));
-        let fallback_service = try_ready!(self.fallback.poll().map_err(Into::into));
+        let mut stages = Vec::with_capacity(self.stages.len());
+        for stage in &mut self.stages {
+            stages.push(try_ready!(stage.poll().map_err(Into::into)));
+        }
+        let upgrade = match self.upgrade_future {
+            Some(ref mut upgrade) => Some(try_ready!(upgrade.poll().map_err(Into::into))),
+            None => None,
+        };
 
         let svc = Service {
-            primary_service,
-            fallback_service,
+            stages,
+            should_fallback: self.should_fallback.clone(),
+            upgrade,
             _marker: PhantomData,
         };
         Ok(svc.into())
@@ -170,163 +414,564 @@ where
 
 // === impl Service ===
 
-impl<A, B, P, Q, R> svc::Service<http::Request<P>> for Service<A, B, P>
+impl<S, US, P, Q> svc::Service<http::Request<P>> for Service<S, P, Q, US>
 where
-    P: Payload,
-    P::Error: Into<Error>,
-    A: svc::Service<http::Request<P>, Response = http::Response<Q>, Error = Fallback<P>>,
+    P: Payload + Default,
+    S: svc::Service<http::Request<P>, Response = http::Response<Q>, Error = Fallback<P>> + Clone,
     Q: Payload,
-    Q::Error: Into<Error>,
-    B: svc::Service<http::Request<P>, Response = http::Response<R>> + Clone,
-    B::Error: Into<Error>,
-    R: Payload,
-    R::Error: Into<Error>,
+    US: svc::Service<http::Request<P>, Response = http::Response<Q>, Error = Error>,
 {
-    type Response = http::Response<Body<Q, R>>;
+    type Response = http::Response<Q>;
     type Error = Error;
-    type Future = ResponseFuture<A::Future, B, P>;
+    type Future = ResponseFuture<S, US, P>;
 
     fn poll_ready(&mut self) -> Poll<(), Self::Error> {
-        use svc::Service;
-
-        match self.primary_service.poll_ready() {
-            Ok(ready) => Ok(ready),
-            Err(Fallback::Inner(e)) => Err(e),
+        match self.stages[0].poll_ready() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(())) => {}
+            Err(Fallback::Inner(e)) => return Err(e),
             Err(Fallback::Rejected(_)) => unreachable!("poll_ready must not reject a request"),
         }
+        if let Some(ref mut upgrade) = self.upgrade {
+            try_ready!(upgrade.poll_ready().map_err(Into::into));
+        }
+        Ok(Async::Ready(()))
     }
 
     fn call(&mut self, req: http::Request<P>) -> Self::Future {
-        use svc::Service;
+        if wants_upgrade(&req) {
+            if let Some(ref mut upgrade) = self.upgrade {
+                return ResponseFuture::Upgrade(upgrade.call(req));
+            }
+        }
 
-        let future = self.primary_service.call(req);
-        let fallback = self.fallback_service.clone();
-        ResponseFuture::Primary { future, fallback }
+        // A retried request is rebuilt without its body, so only capture
+        // `head` when there's no body to lose.
+        let head = if self.should_fallback.is_some() && req.body().is_end_stream() {
+            Some(RequestHead::capture(&req))
+        } else {
+            None
+        };
+        let stages = self.stages[1..].to_vec();
+        let future = self.stages[0].call(req);
+        ResponseFuture::Pending {
+            future,
+            stages,
+            should_fallback: self.should_fallback.clone(),
+            head,
+        }
     }
 }
 
 // === impl ResponseFuture ===
 
-impl<A, B, P, Q, R> Future for ResponseFuture<A, B, P>
+impl<S, US, P, Q> Future for ResponseFuture<S, US, P>
 where
-    A: Future<Item = http::Response<Q>, Error = Fallback<P>>,
-    B: svc::Service<http::Request<P>, Response = http::Response<R>> + Clone,
-    B::Error: Into<Error>,
-    P: Payload,
+    S: svc::Service<http::Request<P>, Response = http::Response<Q>, Error = Fallback<P>> + Clone,
+    US: svc::Service<http::Request<P>, Response = http::Response<Q>, Error = Error>,
+    P: Payload + Default,
     Q: Payload,
-    R: Payload,
 {
-    type Item = http::Response<Body<Q, R>>;
+    type Item = http::Response<Q>;
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         loop {
             *self = match self {
-                ResponseFuture::Primary {
+                ResponseFuture::Pending {
                     ref mut future,
-                    ref fallback,
+                    ref mut stages,
+                    ref mut should_fallback,
+                    ref mut head,
                 } => match future.poll() {
-                    Ok(Async::Ready(rsp)) => return Ok(rsp.map(Body::A).into()),
+                    Ok(Async::Ready(rsp)) => {
+                        // `head` is `None` when the body can't be replayed.
+                        let retry = !stages.is_empty()
+                            && head.is_some()
+                            && should_fallback
+                                .as_ref()
+                                .map_or(false, |should_fallback| should_fallback(&rsp));
+                        if !retry {
+                            return Ok(rsp.into());
+                        }
+                        let req = head.clone().expect("checked above").into_request();
+                        let next = stages.remove(0);
+                        ResponseFuture::NextReady {
+                            next,
+                            stages: mem::replace(stages, Vec::new()),
+                            should_fallback: should_fallback.clone(),
+                            head: head.take(),
+                            request: Some(req),
+                        }
+                    }
                     Err(Fallback::Inner(e)) => return Err(e),
-                    Err(Fallback::Rejected(req)) => ResponseFuture::FallbackPending {
-                        fallback: fallback.clone(),
-                        request: Some(req),
-                    },
+                    Err(Fallback::Rejected(rejected)) => {
+                        if stages.is_empty() {
+                            return Err(NoFallbackAvailable(rejected.reason).into());
+                        }
+                        let next = stages.remove(0);
+                        ResponseFuture::NextReady {
+                            next,
+                            stages: mem::replace(stages, Vec::new()),
+                            should_fallback: should_fallback.clone(),
+                            head: head.take(),
+                            request: Some(rejected.into_request()),
+                        }
+                    }
                 },
 
-                ResponseFuture::FallbackPending {
-                    ref mut fallback,
+                ResponseFuture::NextReady {
+                    ref mut next,
+                    ref mut stages,
+                    ref mut should_fallback,
+                    ref mut head,
                     ref mut request,
                 } => {
-                    try_ready!(fallback.poll_ready().map_err(Into::into));
+                    match next.poll_ready() {
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        Ok(Async::Ready(())) => {}
+                        Err(Fallback::Inner(e)) => return Err(e),
+                        Err(Fallback::Rejected(_)) => {
+                            unreachable!("poll_ready must not reject a request")
+                        }
+                    }
                     let req = request.take().expect("poll after ready");
-                    ResponseFuture::Fallback(fallback.call(req))
+                    let future = next.call(req);
+                    ResponseFuture::Pending {
+                        future,
+                        stages: mem::replace(stages, Vec::new()),
+                        should_fallback: should_fallback.clone(),
+                        head: head.take(),
+                    }
                 }
 
-                ResponseFuture::Fallback(ref mut f) => {
-                    let rsp = try_ready!(f.poll().map_err(Into::into));
-                    return Ok(rsp.map(Body::B).into());
+                ResponseFuture::Upgrade(ref mut future) => {
+                    let rsp = try_ready!(future.poll().map_err(Into::into));
+                    return Ok(rsp.into());
                 }
             }
         }
     }
 }
 
-// === impl Body ===
+// === impl BoxBody ===
+
+impl BoxBody {
+    /// Boxes a `Payload` whose error is convertible to `Error`, erasing its
+    /// concrete body and buffer types.
+    pub fn new<B>(inner: B) -> Self
+    where
+        B: Payload + Send + 'static,
+        B::Error: Into<Error>,
+    {
+        BoxBody(Box::new(EraseBody(inner)))
+    }
+}
+
+impl Payload for BoxBody {
+    type Data = BoxBuf;
+    type Error = Error;
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, Self::Error> {
+        self.0.poll_data()
+    }
+
+    fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, Self::Error> {
+        self.0.poll_trailers()
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_end_stream()
+    }
+}
+
+// === impl BoxBuf ===
+
+impl BoxBuf {
+    fn new<B: Buf + Send + 'static>(buf: B) -> Self {
+        BoxBuf(Box::new(buf))
+    }
+}
+
+impl Buf for BoxBuf {
+    fn remaining(&self) -> usize {
+        self.0.remaining()
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.0.bytes()
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        self.0.advance(cnt)
+    }
+}
+
+// === impl EraseBody ===
 
-impl<A, B> Payload for Body<A, B>
+impl<B> Payload for EraseBody<B>
 where
-    A: Payload,
-    B: Payload<Error = A::Error>,
+    B: Payload,
+    B::Error: Into<Error>,
 {
-    type Data = Body<A::Data, B::Data>;
-    type Error = A::Error;
+    type Data = BoxBuf;
+    type Error = Error;
 
     fn poll_data(&mut self) -> Poll<Option<Self::Data>, Self::Error> {
-        match self {
-            Body::A(ref mut body) => body.poll_data().map(|r| r.map(|o| o.map(Body::A))),
-            Body::B(ref mut body) => body.poll_data().map(|r| r.map(|o| o.map(Body::B))),
-        }
+        self.0
+            .poll_data()
+            .map(|async_| async_.map(|opt| opt.map(BoxBuf::new)))
+            .map_err(Into::into)
     }
 
     fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, Self::Error> {
-        match self {
-            Body::A(ref mut body) => body.poll_trailers(),
-            Body::B(ref mut body) => body.poll_trailers(),
-        }
+        self.0.poll_trailers().map_err(Into::into)
     }
 
     fn is_end_stream(&self) -> bool {
-        match self {
-            Body::A(ref body) => body.is_end_stream(),
-            Body::B(ref body) => body.is_end_stream(),
-        }
+        self.0.is_end_stream()
     }
 }
 
-impl<A, B: Default> Default for Body<A, B> {
-    fn default() -> Self {
-        Body::B(Default::default())
+// === impl EraseResponseBody ===
+
+/// Wraps a `Layer` so that its `Service` responds with `BoxBody` instead
+/// of its inner body type.
+pub fn erase_body<L>(inner: L) -> EraseResponseBodyLayer<L> {
+    EraseResponseBodyLayer(inner)
+}
+
+pub struct EraseResponseBodyLayer<L>(L);
+
+impl<L, M> svc::Layer<M> for EraseResponseBodyLayer<L>
+where
+    L: svc::Layer<M>,
+{
+    type Service = EraseResponseBodyMakeSvc<L::Service>;
+
+    fn layer(&self, inner: M) -> Self::Service {
+        EraseResponseBodyMakeSvc(self.0.layer(inner))
     }
 }
 
-impl<A, B> Body<A, B>
+pub struct EraseResponseBodyMakeSvc<M>(M);
+
+impl<M, T> svc::Service<T> for EraseResponseBodyMakeSvc<M>
 where
-    A: Payload,
-    B: Payload<Error = A::Error>,
+    M: svc::Service<T>,
 {
-    fn rsp_a(rsp: http::Response<A>) -> http::Response<Self> {
-        rsp.map(Body::A)
+    type Response = EraseResponseBodyService<M::Response>;
+    type Error = M::Error;
+    type Future = EraseResponseBodyMakeFuture<M::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.0.poll_ready()
     }
 
-    fn rsp_b(rsp: http::Response<B>) -> http::Response<Self> {
-        rsp.map(Body::B)
+    fn call(&mut self, target: T) -> Self::Future {
+        EraseResponseBodyMakeFuture(self.0.call(target))
     }
 }
 
-impl<A, B> Buf for Body<A, B>
+pub struct EraseResponseBodyMakeFuture<F>(F);
+
+impl<F> Future for EraseResponseBodyMakeFuture<F>
 where
-    A: Buf,
-    B: Buf,
+    F: Future,
 {
-    fn remaining(&self) -> usize {
-        match self {
-            Body::A(ref buf) => buf.remaining(),
-            Body::B(ref buf) => buf.remaining(),
+    type Item = EraseResponseBodyService<F::Item>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let svc = try_ready!(self.0.poll());
+        Ok(EraseResponseBodyService(svc).into())
+    }
+}
+
+#[derive(Clone)]
+pub struct EraseResponseBodyService<S>(S);
+
+impl<S, P, Q> svc::Service<http::Request<P>> for EraseResponseBodyService<S>
+where
+    S: svc::Service<http::Request<P>, Response = http::Response<Q>>,
+    S::Error: Into<Error>,
+    Q: Payload + Send + 'static,
+    Q::Error: Into<Error>,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Error;
+    type Future = EraseResponseBodyFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.0.poll_ready().map_err(Into::into)
+    }
+
+    fn call(&mut self, req: http::Request<P>) -> Self::Future {
+        EraseResponseBodyFuture(self.0.call(req))
+    }
+}
+
+pub struct EraseResponseBodyFuture<F>(F);
+
+impl<F, Q> Future for EraseResponseBodyFuture<F>
+where
+    F: Future<Item = http::Response<Q>>,
+    F::Error: Into<Error>,
+    Q: Payload + Send + 'static,
+    Q::Error: Into<Error>,
+{
+    type Item = http::Response<BoxBody>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let rsp = try_ready!(self.0.poll().map_err(Into::into));
+        Ok(rsp.map(BoxBody::new).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures::future;
+    use hyper::Body;
+
+    struct FixedPayload {
+        data: Option<Bytes>,
+        trailers: Option<http::HeaderMap>,
+    }
+
+    impl Payload for FixedPayload {
+        type Data = Bytes;
+        type Error = Error;
+
+        fn poll_data(&mut self) -> Poll<Option<Self::Data>, Self::Error> {
+            Ok(Async::Ready(self.data.take()))
+        }
+
+        fn poll_trailers(&mut self) -> Poll<Option<http::HeaderMap>, Self::Error> {
+            Ok(Async::Ready(self.trailers.take()))
+        }
+
+        fn is_end_stream(&self) -> bool {
+            self.data.is_none() && self.trailers.is_none()
         }
     }
 
-    fn bytes(&self) -> &[u8] {
-        match self {
-            Body::A(ref buf) => buf.bytes(),
-            Body::B(ref buf) => buf.bytes(),
+    #[test]
+    fn box_body_forwards_data_trailers_and_end_stream() {
+        let mut trailers = http::HeaderMap::new();
+        trailers.insert("x-trailer", http::HeaderValue::from_static("1"));
+
+        let inner = FixedPayload {
+            data: Some(Bytes::from_static(b"hello")),
+            trailers: Some(trailers.clone()),
+        };
+        let mut body = BoxBody::new(inner);
+        assert!(!body.is_end_stream());
+
+        match body.poll_data().expect("poll_data must not error") {
+            Async::Ready(Some(mut buf)) => assert_eq!(buf.bytes(), b"hello"),
+            _ => panic!("expected a ready data chunk"),
+        }
+        assert!(!body.is_end_stream(), "trailers are still pending");
+
+        match body.poll_trailers().expect("poll_trailers must not error") {
+            Async::Ready(Some(got)) => assert_eq!(got, trailers),
+            _ => panic!("expected ready trailers"),
         }
+        assert!(body.is_end_stream());
     }
 
-    fn advance(&mut self, cnt: usize) {
-        match self {
-            Body::A(ref mut buf) => buf.advance(cnt),
-            Body::B(ref mut buf) => buf.advance(cnt),
+    #[derive(Clone)]
+    struct Reject;
+
+    impl svc::Service<http::Request<Body>> for Reject {
+        type Response = http::Response<Body>;
+        type Error = Fallback<Body>;
+        type Future = future::FutureResult<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, req: http::Request<Body>) -> Self::Future {
+            future::err(Fallback::Rejected(Rejected::new(
+                RejectReason::Declined,
+                req,
+            )))
+        }
+    }
+
+    #[test]
+    fn exhausted_chain_returns_no_fallback_available() {
+        let mut svc = Service {
+            stages: vec![Reject, Reject],
+            should_fallback: None,
+            upgrade: None::<NoUpgradeService<Body, Body>>,
+            _marker: PhantomData,
+        };
+
+        let req = http::Request::new(Body::empty());
+        let err = svc::Service::call(&mut svc, req)
+            .poll()
+            .expect_err("chain should be exhausted");
+        assert!(format!("{}", err).contains("no more fallback stages"));
+    }
+
+    #[derive(Clone)]
+    struct Respond(u16);
+
+    impl svc::Service<http::Request<Body>> for Respond {
+        type Response = http::Response<Body>;
+        type Error = Fallback<Body>;
+        type Future = future::FutureResult<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, _req: http::Request<Body>) -> Self::Future {
+            let mut rsp = http::Response::new(Body::empty());
+            *rsp.status_mut() = http::StatusCode::from_u16(self.0).unwrap();
+            future::ok(rsp)
+        }
+    }
+
+    fn unavailable(rsp: &http::Response<Body>) -> bool {
+        rsp.status() == http::StatusCode::SERVICE_UNAVAILABLE
+    }
+
+    fn poll_ready_response<US>(fut: &mut ResponseFuture<Respond, US, Body>) -> http::Response<Body>
+    where
+        US: svc::Service<http::Request<Body>, Response = http::Response<Body>, Error = Error>,
+    {
+        loop {
+            match fut.poll().expect("must not error") {
+                Async::Ready(rsp) => return rsp,
+                Async::NotReady => continue,
+            }
+        }
+    }
+
+    #[test]
+    fn predicate_retries_next_stage_on_matching_response() {
+        let mut svc = Service {
+            stages: vec![Respond(503), Respond(200)],
+            should_fallback: Some(Arc::new(unavailable) as ShouldFallback<Body>),
+            upgrade: None::<NoUpgradeService<Body, Body>>,
+            _marker: PhantomData,
+        };
+
+        let req = http::Request::builder()
+            .method(http::Method::GET)
+            .body(Body::empty())
+            .unwrap();
+        let rsp = poll_ready_response(&mut svc::Service::call(&mut svc, req));
+        assert_eq!(rsp.status(), http::StatusCode::OK);
+    }
+
+    #[test]
+    fn predicate_is_skipped_when_request_has_a_body() {
+        let mut svc = Service {
+            stages: vec![Respond(503), Respond(200)],
+            should_fallback: Some(Arc::new(unavailable) as ShouldFallback<Body>),
+            upgrade: None::<NoUpgradeService<Body, Body>>,
+            _marker: PhantomData,
+        };
+
+        let req = http::Request::builder()
+            .method(http::Method::POST)
+            .body(Body::from("hello"))
+            .unwrap();
+        // the body can't be replayed, so the primary's response stands
+        let rsp = poll_ready_response(&mut svc::Service::call(&mut svc, req));
+        assert_eq!(rsp.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn wants_upgrade_routes_connect_and_connection_upgrade() {
+        let req = http::Request::builder()
+            .method(http::Method::CONNECT)
+            .uri("example.com:443")
+            .body(Body::empty())
+            .unwrap();
+        assert!(wants_upgrade(&req));
+
+        let req = http::Request::builder()
+            .method(http::Method::GET)
+            .body(Body::empty())
+            .unwrap();
+        assert!(!wants_upgrade(&req));
+
+        let req = http::Request::builder()
+            .method(http::Method::GET)
+            .header(http::header::CONNECTION, "upgrade")
+            .header(http::header::UPGRADE, "websocket")
+            .body(Body::empty())
+            .unwrap();
+        assert!(wants_upgrade(&req));
+
+        // `Connection: upgrade` without an `Upgrade` header isn't one.
+        let req = http::Request::builder()
+            .method(http::Method::GET)
+            .header(http::header::CONNECTION, "upgrade")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!wants_upgrade(&req));
+    }
+
+    #[derive(Clone)]
+    struct UpgradeMarker;
+
+    impl svc::Service<http::Request<Body>> for UpgradeMarker {
+        type Response = http::Response<Body>;
+        type Error = Error;
+        type Future = future::FutureResult<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+            Ok(Async::Ready(()))
         }
+
+        fn call(&mut self, _req: http::Request<Body>) -> Self::Future {
+            let mut rsp = http::Response::new(Body::empty());
+            *rsp.status_mut() = http::StatusCode::SWITCHING_PROTOCOLS;
+            future::ok(rsp)
+        }
+    }
+
+    #[test]
+    fn connect_requests_are_dispatched_to_the_upgrade_service() {
+        let mut svc = Service {
+            stages: vec![Respond(200)],
+            should_fallback: None,
+            upgrade: Some(UpgradeMarker),
+            _marker: PhantomData,
+        };
+
+        let req = http::Request::builder()
+            .method(http::Method::CONNECT)
+            .uri("example.com:443")
+            .body(Body::empty())
+            .unwrap();
+        let rsp = poll_ready_response(&mut svc::Service::call(&mut svc, req));
+        assert_eq!(rsp.status(), http::StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[test]
+    fn ordinary_requests_still_go_through_the_stage_chain_when_upgrade_is_configured() {
+        let mut svc = Service {
+            stages: vec![Respond(200)],
+            should_fallback: None,
+            upgrade: Some(UpgradeMarker),
+            _marker: PhantomData,
+        };
+
+        let req = http::Request::builder()
+            .method(http::Method::GET)
+            .body(Body::empty())
+            .unwrap();
+        let rsp = poll_ready_response(&mut svc::Service::call(&mut svc, req));
+        assert_eq!(rsp.status(), http::StatusCode::OK);
     }
 }